@@ -1,5 +1,6 @@
 use sqlx::{mysql::{MySqlConnectOptions, MySqlPoolOptions}, MySqlPool};
 use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::time::Duration;
 use config::{Config, File};
 use dotenv::dotenv;
@@ -8,9 +9,48 @@ use bigdecimal::BigDecimal;
 use std::str::FromStr;
 use sqlx::types::time::PrimitiveDateTime;
 
+// 仅支持 TRC20 代币：轮询的是 TRC20 转账流水接口，按 token_info.address 匹配，
+// 原生 TRX 转账不会出现在这个流水里，配置里不要填 TRX，contract 必须是真实合约地址
+#[derive(Debug, Deserialize, Clone)]
+struct TokenConfig {
+    symbol: String,
+    contract: String,
+    decimals: u32,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct WatchedAccount {
+    address: String,
+    network: String, // "mainnet" 或 "nile"
+    tokens: Vec<TokenConfig>,
+}
+
+// 校验 settings.accounts 里的 token 配置都是带合约地址的 TRC20 代币。
+// 当前实现只轮询 TRC20 转账流水，原生 TRX 转账不会出现在这个流水里，
+// 配了 contract 为空的条目（比如 TRX）会一直扫描不到、永远无法入账，
+// 与其静默失配，不如启动时就拒绝
+fn validate_token_configs(accounts: &[WatchedAccount]) -> Result<(), String> {
+    for account in accounts {
+        for token in &account.tokens {
+            if token.contract.trim().is_empty() {
+                return Err(format!(
+                    "账户 {} 配置的代币 {} 缺少 contract 地址：本服务只支持 TRC20 代币，\
+                     原生 TRX 转账不会出现在 TRC20 流水里，无法匹配，请移除该配置",
+                    account.address, token.symbol
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug, Deserialize)]
 struct Settings {
-    address: String,
+    accounts: Vec<WatchedAccount>,
+    // 入账前需要等待的确认区块数，防止链重组导致的双花/回滚
+    required_confirmations: i64,
+    // 未支付订单的宽限期（秒），超过后标记为过期，不再纳入扫描范围
+    payment_grace_period_sec: i64,
 }
 
 #[derive(Deserialize, Debug)]
@@ -32,6 +72,7 @@ struct TransactionDetail {
 #[derive(Deserialize, Debug)]
 struct TokenInfo {
     symbol: String,
+    address: String, // 合约地址，用于匹配监控的代币
     decimals: u32, // 添加 decimals 字段
 }
 
@@ -41,6 +82,194 @@ struct PendingRecord {
     pay_token: BigDecimal,
 }
 
+// 处于“确认中”状态（is_pay = 2）、等待达到所需确认数的记录。
+// matched_amount 是命中时链上转账的实际金额，单独存放而不覆盖 pay_token，
+// 这样如果交易被重组回滚、记录退回待支付，pay_token 仍然是订单本来要求的金额，
+// 按金额分桶的索引（amount_key）才不会因为一次失败的匹配而漂移。
+#[derive(Debug)]
+struct ConfirmingRecord {
+    id: i64,
+    transaction_id: String,
+    matched_amount: BigDecimal,
+    confirm_block: i64,
+}
+
+#[derive(Deserialize, Debug)]
+struct NowBlockResponse {
+    block_header: BlockHeader,
+}
+
+#[derive(Deserialize, Debug)]
+struct BlockHeader {
+    raw_data: BlockRawData,
+}
+
+#[derive(Deserialize, Debug)]
+struct BlockRawData {
+    number: i64,
+}
+
+#[derive(Deserialize, Debug)]
+struct TransactionInfoResponse {
+    #[serde(rename = "blockNumber")]
+    block_number: Option<i64>,
+}
+
+// 持久化的分页游标，记录某个账户上一次成功处理到的位置，用于崩溃后续跑
+#[derive(Debug)]
+struct SyncState {
+    last_tx_id: Option<String>,
+    next_page_token: Option<String>,
+}
+
+// 根据网络名称返回对应的 TronGrid 域名
+fn trongrid_base_url(network: &str) -> &'static str {
+    match network {
+        "mainnet" => "https://api.trongrid.io",
+        _ => "https://nile.trongrid.io",
+    }
+}
+
+// 将金额归一化为 3 位小数后的毫厘整数，用作待支付记录的索引键
+fn amount_key(amount: &BigDecimal) -> i64 {
+    let milli = (amount * BigDecimal::from(1000)).with_scale(0);
+    milli.to_string().parse::<i64>().unwrap_or(0)
+}
+
+// 查询链上当前最新区块高度，用于计算确认深度
+async fn get_current_block_number(base_url: &str) -> Result<i64, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let url = format!("{}/wallet/getnowblock", base_url);
+    let response: NowBlockResponse = reqwest::get(&url).await?.json().await?;
+    Ok(response.block_header.raw_data.number)
+}
+
+// MySQL GET_LOCK() 的锁名自 5.7.5 起被截断到 64 字节，超长会直接报
+// ER_USER_LOCK_WRONG_NAME。Tron 的 txID 是 64 位十六进制哈希，"pay_tx_lock:" 前缀
+// 一加就超限，所以这里不能直接拼 tx_id，改成对 tx_id 做定长哈希摘要再拼前缀。
+fn tx_lock_key(tx_id: &str) -> String {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    let mut hasher = DefaultHasher::new();
+    tx_id.hash(&mut hasher);
+    format!("pay_tx_lock:{:016x}", hasher.finish())
+}
+
+// 基于 MySQL 命名锁（GET_LOCK / RELEASE_LOCK）为单笔交易 ID 加互斥锁，
+// 防止多个实例（或一次误启动的重复进程）同时匹配同一笔链上转账导致重复入账。
+// 锁必须在同一个连接上获取和释放，因此成功时把持有锁的连接一并返回。
+async fn acquire_tx_lock(
+    pool: &MySqlPool,
+    tx_id: &str,
+) -> Result<Option<sqlx::pool::PoolConnection<sqlx::MySql>>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let mut conn = pool.acquire().await?;
+    let lock_key = tx_lock_key(tx_id);
+    let acquired: Option<i64> = sqlx::query_scalar("SELECT GET_LOCK(?, 2)")
+        .bind(&lock_key)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    if acquired == Some(1) {
+        Ok(Some(conn))
+    } else {
+        Ok(None)
+    }
+}
+
+// 释放之前在同一连接上获取的命名锁
+async fn release_tx_lock(
+    conn: &mut sqlx::pool::PoolConnection<sqlx::MySql>,
+    tx_id: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let lock_key = tx_lock_key(tx_id);
+    let _: Option<i64> = sqlx::query_scalar("SELECT RELEASE_LOCK(?)")
+        .bind(&lock_key)
+        .fetch_one(&mut **conn)
+        .await?;
+    Ok(())
+}
+
+// 查询某笔交易当前所在的区块高度；交易若已被重组回滚则查不到，返回 None
+async fn get_transaction_block_number(
+    base_url: &str,
+    tx_id: &str,
+) -> Result<Option<i64>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let url = format!("{}/walletsolidity/gettransactioninfobyid", base_url);
+    let body = format!("{{\"value\":\"{}\"}}", tx_id);
+    let client = reqwest::Client::new();
+    let response = client
+        .post(&url)
+        .header("Content-Type", "application/json")
+        .body(body)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Ok(None);
+    }
+
+    let info: TransactionInfoResponse = response.json().await.unwrap_or(TransactionInfoResponse { block_number: None });
+    Ok(info.block_number)
+}
+
+// 读取某账户上一次持久化的分页游标
+async fn load_sync_state(
+    pool: &MySqlPool,
+    address: &str,
+) -> Result<Option<SyncState>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let state = sqlx::query_as!(
+        SyncState,
+        "SELECT last_tx_id, next_page_token FROM sync_state WHERE address = ?",
+        address
+    )
+        .fetch_optional(pool)
+        .await?;
+    Ok(state)
+}
+
+// 保存分页游标：记录本次成功处理到的 txID 和下一页 token，崩溃重启后可以跳过已下载过的页面
+async fn save_sync_state(
+    pool: &MySqlPool,
+    address: &str,
+    last_tx_id: &str,
+    next_page_token: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    sqlx::query!(
+        "INSERT INTO sync_state (address, last_tx_id, next_page_token)
+         VALUES (?, ?, ?)
+         ON DUPLICATE KEY UPDATE last_tx_id = VALUES(last_tx_id), next_page_token = VALUES(next_page_token)",
+        address,
+        last_tx_id,
+        next_page_token
+    )
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// 一轮扫描自然结束（追上已处理记录、分页耗尽或记录全部匹配完）后清空游标，
+// 下一轮重新从当前最早未支付记录计算起点
+async fn clear_sync_state(
+    pool: &MySqlPool,
+    address: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    sqlx::query!("DELETE FROM sync_state WHERE address = ?", address)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// 解析命令行中的一次性回填参数：--backfill <from_timestamp>
+fn parse_backfill_arg() -> Option<i64> {
+    let args: Vec<String> = env::args().collect();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--backfill" {
+            return iter.next().and_then(|v| v.parse::<i64>().ok());
+        }
+    }
+    None
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
     dotenv().ok(); // 加载 .env 文件
@@ -51,6 +280,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>
         .build()?
         .try_deserialize::<Settings>()?;
 
+    // 只支持 TRC20 代币，提前拒绝缺少 contract 地址的配置（例如误填的原生 TRX）
+    validate_token_configs(&settings.accounts)?;
+
     // 获取数据库连接字符串
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
 
@@ -63,62 +295,481 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>
         .connect_with(options)
         .await?;
 
-    let address = settings.address.clone();
+    // 显式的一次性回填模式：--backfill <from_timestamp>，只补录历史记录，不进入实时轮询循环
+    if let Some(from_timestamp) = parse_backfill_arg() {
+        println!("以一次性回填模式启动，起始时间戳: {}", from_timestamp);
+        for account in settings.accounts {
+            run_backfill(pool.clone(), account, from_timestamp).await?;
+        }
+        println!("回填模式处理完毕，退出。");
+        return Ok(());
+    }
+
+    // 为每个监控账户各启动一个独立的轮询任务，互不影响
+    let required_confirmations = settings.required_confirmations;
+    let payment_grace_period_sec = settings.payment_grace_period_sec;
+    let mut handles = Vec::new();
+    for account in settings.accounts {
+        let pool = pool.clone();
+        let handle = tokio::spawn(async move {
+            run_watch_account_with_restart(account, pool, required_confirmations, payment_grace_period_sec).await;
+        });
+        handles.push(handle);
+    }
+
+    for handle in handles {
+        let _ = handle.await;
+    }
+
+    Ok(())
+}
+
+// watch_account 内部任一环节出错都会直接 ? 出来，不会自愈；这里负责兜底重启，
+// 避免单笔交易触发的偶发错误（网络抖动、临时锁冲突等）永久杀死该账户的监听任务。
+// 采用简单的固定退避，失败后等一会儿重新进入循环，而不是让任务直接退出。
+async fn run_watch_account_with_restart(
+    account: WatchedAccount,
+    pool: MySqlPool,
+    required_confirmations: i64,
+    payment_grace_period_sec: i64,
+) {
+    const RESTART_BACKOFF: Duration = Duration::from_secs(10);
+    loop {
+        let result = watch_account(
+            account.clone(),
+            pool.clone(),
+            required_confirmations,
+            payment_grace_period_sec,
+        )
+        .await;
+
+        if let Err(e) = result {
+            eprintln!(
+                "账户 {} 的监听任务出错，{} 秒后重试: {}",
+                account.address,
+                RESTART_BACKOFF.as_secs(),
+                e
+            );
+            tokio::time::sleep(RESTART_BACKOFF).await;
+            continue;
+        }
+
+        // watch_account 正常情况下是一个不会返回 Ok 的无限循环，这里只是防御性兜底
+        break;
+    }
+}
+
+// 单个账户的轮询主循环，持续监测 is_pay = 0 的记录
+async fn watch_account(
+    account: WatchedAccount,
+    pool: MySqlPool,
+    required_confirmations: i64,
+    payment_grace_period_sec: i64,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let address = account.address.clone();
+    let base_url = trongrid_base_url(&account.network);
 
-    // 无限循环，持续监测 is_pay = 0 的记录
     loop {
+        // 每轮都先推进确认中的记录，不依赖是否还有新的待支付记录
+        reconcile_confirming_records(&pool, base_url, &address, required_confirmations).await?;
+
+        // 清理超过宽限期仍未支付的订单，避免 min_timestamp 扫描窗口被无限拉长
+        expire_stale_pending_records(&pool, &address, payment_grace_period_sec).await?;
+
         // 获取最早的未支付记录的创建时间
         let min_create_time: Option<PrimitiveDateTime> = sqlx::query_scalar!(
-            "SELECT create_time FROM pay_records WHERE is_pay = 0 ORDER BY id ASC LIMIT 1"
+            "SELECT create_time FROM pay_records WHERE is_pay = 0 AND address = ? ORDER BY id ASC LIMIT 1",
+            address
         )
             .fetch_optional(&pool)
             .await?;
 
         if let Some(min_time) = min_create_time {
             // 打印人类可读的日期和时间
-            println!("最早的未支付记录创建时间: {}", min_time);
+            println!("[{}] 最早的未支付记录创建时间: {}", address, min_time);
 
             let min_timestamp = min_time.assume_utc().unix_timestamp();
-            println!("最小创建时间的 UNIX 时间戳: {}", min_timestamp); // 打印 min_timestamp
-            // 正式网的链接
-            // let trc20_url = format!(
-            //     "https://api.trongrid.io/v1/accounts/{}/transactions/trc20?only_confirmed=true&limit=10",
-            //     address
-            // );
+            println!("[{}] 最小创建时间的 UNIX 时间戳: {}", address, min_timestamp);
+
             let trc20_url = format!(
-                "https://nile.trongrid.io/v1/accounts/{}/transactions/trc20?only_confirmed=true&limit=10&min_timestamp={}",
+                "{}/v1/accounts/{}/transactions/trc20?only_confirmed=true&limit=10&min_timestamp={}",
+                base_url,
                 address,
                 min_timestamp
             );
 
-            println!("发现未支付的记录，开始处理...");
-            fetch_and_process_transactions(trc20_url.clone(), pool.clone(), address.clone()).await?;
+            // 如果上次处理中途崩溃，优先从持久化的分页游标恢复，避免重新下载已经走过的页面
+            let resume_next_page = load_sync_state(&pool, &address)
+                .await?
+                .and_then(|state| state.next_page_token);
+
+            println!("[{}] 发现未支付的记录，开始处理...", address);
+            fetch_and_process_transactions(trc20_url.clone(), pool.clone(), address.clone(), base_url, &account.tokens, resume_next_page).await?;
         } else {
-            println!("没有未支付的记录，等待中...");
+            println!("[{}] 没有未支付的记录，等待中...", address);
             tokio::time::sleep(Duration::from_secs(10)).await;
         }
     }
 }
 
+// 一次性回填模式：从指定的历史时间戳开始走一遍 TRC20 流水，补录服务未运行期间产生的记录。
+// 复用与实时轮询相同的匹配/确认状态流转逻辑，但只跑一轮，不进入 watch_account 的轮询循环。
+async fn run_backfill(
+    pool: MySqlPool,
+    account: WatchedAccount,
+    from_timestamp: i64,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let address = account.address.clone();
+    let base_url = trongrid_base_url(&account.network);
+
+    let trc20_url = format!(
+        "{}/v1/accounts/{}/transactions/trc20?only_confirmed=true&limit=10&min_timestamp={}",
+        base_url, address, from_timestamp
+    );
+
+    println!("[{}] 回填模式：从时间戳 {} 开始补录历史记录", address, from_timestamp);
+    fetch_and_process_transactions(trc20_url, pool, address.clone(), base_url, &account.tokens, None).await?;
+    println!("[{}] 回填完成", address);
+
+    Ok(())
+}
+
+// 将命中的记录转入“确认中”状态（is_pay = 2），记录命中时所在的区块高度。
+// 对 tx_id 加互斥锁防止多个实例并发匹配同一笔链上转账；锁必须在同一连接上
+// 获取和释放，因此无论内部流程成功还是出错，都要在返回前释放，避免连接回池
+// 后把锁悬挂在一个谁都不知道的会话上，长期饿死这个 tx_id 的后续尝试。
+async fn mark_record_confirming(
+    pool: &MySqlPool,
+    base_url: &str,
+    address: &str,
+    tx_id: &str,
+    readable_amount: &BigDecimal,
+    id: i64,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let mut lock_conn = match acquire_tx_lock(pool, tx_id).await? {
+        Some(conn) => conn,
+        None => {
+            println!("[{}] 交易 {} 正被其他实例处理，本轮跳过", address, tx_id);
+            return Ok(());
+        }
+    };
+
+    let result = do_mark_record_confirming(&mut lock_conn, base_url, address, tx_id, readable_amount, id).await;
+
+    // 不管上面结果如何都要在同一连接上释放锁
+    let release_result = release_tx_lock(&mut lock_conn, tx_id).await;
+
+    result?;
+    release_result?;
+    Ok(())
+}
+
+// 实际执行状态流转，只在持有 tx_id 互斥锁期间调用
+async fn do_mark_record_confirming(
+    lock_conn: &mut sqlx::pool::PoolConnection<sqlx::MySql>,
+    base_url: &str,
+    address: &str,
+    tx_id: &str,
+    readable_amount: &BigDecimal,
+    id: i64,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    // gettransactioninfobyid 查询的是 solidity 节点，相对 only_confirmed 流水列表可能存在索引延迟，
+    // 查不到区块号时不能当成 0 写入：reconcile_confirming_records 会把 confirm_block <= 0
+    // 的记录当作异常数据跳过、一直不入账，记录就会卡在确认中状态。
+    // 这里先不流转状态，保留 is_pay = 0，等下一轮重新匹配、到时候区块号多半已经能查到了。
+    let confirm_block = match get_transaction_block_number(base_url, tx_id).await? {
+        Some(block) if block > 0 => block,
+        _ => {
+            println!(
+                "[{}] 交易 {} 暂时查不到所在区块（节点索引延迟），本轮不标记为确认中，等待下次重试",
+                address, tx_id
+            );
+            return Ok(());
+        }
+    };
+
+    // transaction_id 上有 UNIQUE 约束，即便锁被绕过，同一笔交易
+    // 也只能被唯一一条 pay_records 消费一次。
+    // 命中金额写入 matched_amount 而不是 pay_token，这样即使这笔交易之后被重组回滚，
+    // pay_token 仍保留订单原本要求的金额，回退为待支付后依然能按正确的金额分桶重新匹配。
+    let update_result = sqlx::query!(
+        "UPDATE pay_records
+         SET transaction_id = ?, matched_amount = ?, is_pay = 2, confirm_block = ?
+         WHERE id = ?",
+        tx_id,
+        readable_amount.to_string(),
+        confirm_block,
+        id
+    )
+        .execute(&mut **lock_conn)
+        .await;
+
+    match update_result {
+        Ok(_) => {
+            println!(
+                "[{}] 交易 {} 命中记录 {}，进入确认中状态（区块 {}）",
+                address, tx_id, id, confirm_block
+            );
+            Ok(())
+        }
+        Err(sqlx::Error::Database(db_err)) if db_err.is_unique_violation() => {
+            println!(
+                "[{}] 交易 {} 已被其他记录消费（transaction_id 唯一约束冲突），跳过",
+                address, tx_id
+            );
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// 推进“确认中”（is_pay = 2）的记录：达到所需确认数的正式入账，从链上消失的回退为待支付
+async fn reconcile_confirming_records(
+    pool: &MySqlPool,
+    base_url: &str,
+    address: &str,
+    required_confirmations: i64,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let confirming_records = sqlx::query_as!(
+        ConfirmingRecord,
+        "SELECT id, transaction_id, matched_amount, confirm_block FROM pay_records WHERE is_pay = 2 AND address = ?",
+        address
+    )
+        .fetch_all(pool)
+        .await?;
+
+    if confirming_records.is_empty() {
+        return Ok(());
+    }
+
+    let current_block = get_current_block_number(base_url).await?;
+
+    for record in confirming_records {
+        match get_transaction_block_number(base_url, &record.transaction_id).await? {
+            None => {
+                // 交易在链上查不到了，大概率是被重组回滚，退回待支付状态等待重新匹配。
+                // 只清空 transaction_id/confirm_block/matched_amount，pay_token（订单原本
+                // 要求的金额）从未被改动过，回退后依然能按正确的金额分桶重新匹配。
+                println!(
+                    "[{}] 交易 {} 在确认窗口内消失，记录 {} 回退为待支付",
+                    address, record.transaction_id, record.id
+                );
+                sqlx::query!(
+                    "UPDATE pay_records
+                     SET is_pay = 0, transaction_id = NULL, confirm_block = NULL, matched_amount = NULL
+                     WHERE id = ?",
+                    record.id
+                )
+                    .execute(pool)
+                    .await?;
+            }
+            Some(tx_block) => {
+                // 正常流程下 confirm_block 不会 <= 0（do_mark_record_confirming 已经在匹配时
+                // 拒绝了查不到区块号的交易），这里只是再兜底一层，防止历史脏数据被误入账
+                if record.confirm_block <= 0 {
+                    println!(
+                        "[{}] 记录 {} 的 confirm_block 异常（{}），暂不入账",
+                        address, record.id, record.confirm_block
+                    );
+                    continue;
+                }
+                let confirmations = current_block - tx_block;
+                if confirmations >= required_confirmations {
+                    println!(
+                        "[{}] 交易 {} 已达到 {} 个确认，记录 {} 正式入账",
+                        address, record.transaction_id, confirmations, record.id
+                    );
+                    credit_pay_record(pool, record.id, &record.transaction_id, &record.matched_amount).await?;
+                } else {
+                    println!(
+                        "[{}] 交易 {} 确认中：当前区块 {}，入账区块 {}，还差 {} 个确认",
+                        address,
+                        record.transaction_id,
+                        current_block,
+                        tx_block,
+                        required_confirmations - confirmations
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// 将超过宽限期仍未支付的记录标记为过期（is_pay = 3），
+// 使 min_timestamp 扫描窗口不会被一笔被遗弃的订单无限拉长
+async fn expire_stale_pending_records(
+    pool: &MySqlPool,
+    address: &str,
+    payment_grace_period_sec: i64,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let mut tx = pool.begin().await?;
+
+    let expired_ids: Vec<i64> = sqlx::query_scalar!(
+        "SELECT id FROM pay_records
+         WHERE is_pay = 0 AND address = ? AND create_time < (NOW() - INTERVAL ? SECOND)",
+        address,
+        payment_grace_period_sec
+    )
+        .fetch_all(&mut *tx)
+        .await?;
+
+    if expired_ids.is_empty() {
+        tx.commit().await?;
+        return Ok(());
+    }
+
+    sqlx::query!(
+        "UPDATE pay_records
+         SET is_pay = 3
+         WHERE is_pay = 0 AND address = ? AND create_time < (NOW() - INTERVAL ? SECOND)",
+        address,
+        payment_grace_period_sec
+    )
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    for id in &expired_ids {
+        println!(
+            "[{}] 订单超时：记录 {} 超过宽限期 {} 秒仍未支付，标记为已过期 (is_pay = 3)",
+            address, id, payment_grace_period_sec
+        );
+    }
+
+    Ok(())
+}
+
+// 将达到确认数的记录正式标记为已支付，并结算用户金币。
+// 两个实例（或同一实例里不同账户的 watch_account 任务）都可能并发地为同一笔
+// 交易调用本函数，因此先用 tx_id 的命名锁互斥，再用 is_pay = 2 前置条件 + 行锁兜底。
+async fn credit_pay_record(
+    pool: &MySqlPool,
+    id: i64,
+    tx_id: &str,
+    readable_amount: &BigDecimal,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let mut lock_conn = match acquire_tx_lock(pool, tx_id).await? {
+        Some(conn) => conn,
+        None => {
+            println!("交易 {} 正被其他实例结算中，本轮跳过", tx_id);
+            return Ok(());
+        }
+    };
+
+    let result = do_credit_pay_record(pool, id, tx_id, readable_amount).await;
+
+    // 不管结算是否成功都要在同一连接上释放锁，否则连接回池后锁会被无关任务占用
+    let release_result = release_tx_lock(&mut lock_conn, tx_id).await;
+
+    result?;
+    release_result?;
+    Ok(())
+}
+
+// 实际执行结算的事务，只在持有 tx_id 互斥锁期间调用
+async fn do_credit_pay_record(
+    pool: &MySqlPool,
+    id: i64,
+    tx_id: &str,
+    readable_amount: &BigDecimal,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    // 开启事务
+    let mut tx = pool.begin().await?;
+
+    // 对用户行加行锁，并以 is_pay = 2 为前置条件确认记录还没被其他任务结算过
+    let current = sqlx::query_as::<_, (i64, BigDecimal)>(
+        "SELECT user.id as user_id, user.gold_coins as gold_coins FROM user
+     INNER JOIN pay_records ON user.id = pay_records.user_id
+     WHERE pay_records.id = ? AND pay_records.is_pay = 2
+     FOR UPDATE"
+    )
+        .bind(id) // 绑定 `id` 参数
+        .fetch_optional(&mut *tx)
+        .await?;
+
+    let (user_id, current_gold_coins) = match current {
+        Some(row) => row,
+        None => {
+            // 记录已经不是“确认中”状态，说明已被其他任务结算过，放弃本次重复入账
+            tx.rollback().await?;
+            println!("记录 {} 已被其他任务结算，跳过重复入账", id);
+            return Ok(());
+        }
+    };
+    println!("开始更新记录，交易ID: {}, 用户ID: {}, 金额: {}", tx_id, user_id, readable_amount);
+
+    // 计算 pay_before_gold_coins 和 pay_after_gold_coins
+    let pay_before_gold_coins = current_gold_coins.clone();
+    let pay_after_gold_coins = &current_gold_coins + readable_amount;
+
+    // 再次以 is_pay = 2 为前置条件更新，双重防护并发重复入账
+    let update_result = sqlx::query!(
+        "UPDATE pay_records
+         SET is_pay = 1, pay_before_gold_coins = ?, pay_after_gold_coins = ?
+         WHERE id = ? AND is_pay = 2",
+        pay_before_gold_coins.to_string(),
+        pay_after_gold_coins.to_string(),
+        id
+    )
+        .execute(&mut *tx)
+        .await?;
+
+    if update_result.rows_affected() == 0 {
+        tx.rollback().await?;
+        println!("记录 {} 已被其他任务结算，跳过重复入账", id);
+        return Ok(());
+    }
+
+    // 更新 user 表中的 gold_coins
+    sqlx::query!(
+        "UPDATE user SET gold_coins = ? WHERE id = ?",
+        pay_after_gold_coins.to_string(),
+        user_id
+    )
+        .execute(&mut *tx)
+        .await?;
+
+    // 提交事务
+    tx.commit().await?;
+
+    println!("成功更新记录，id: {}", id);
+
+    Ok(())
+}
+
 // 异步任务处理函数
 async fn fetch_and_process_transactions(
     url: String,
     pool: MySqlPool,
     address: String,
+    base_url: &str,
+    tokens: &[TokenConfig],
+    resume_next_page: Option<String>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
     let mut page = 1;
-    let mut current_url = url;
+    let mut current_url = match resume_next_page {
+        Some(next_page) => {
+            let resumed_url = format!("{}{}", base_url, next_page);
+            println!("[{}] 从上次持久化的分页游标恢复: {}", address, resumed_url);
+            resumed_url
+        }
+        None => url,
+    };
 
     // 1. 获取所有 is_pay = 0 的记录，按 id 升序排序
     let pending_records = sqlx::query_as!(
         PendingRecord,
-        "SELECT id, pay_token FROM pay_records WHERE is_pay = 0 ORDER BY id ASC"
+        "SELECT id, pay_token FROM pay_records WHERE is_pay = 0 AND address = ? ORDER BY id ASC",
+        address
     )
         .fetch_all(&pool)
         .await?;
 
     if pending_records.is_empty() {
-        println!("没有未支付的记录，结束本次处理。");
+        println!("[{}] 没有未支付的记录，结束本次处理。", address);
         return Ok(());
     }
 
@@ -127,25 +778,25 @@ async fn fetch_and_process_transactions(
 
     // 获取小于 min_id 的最大 id 且 is_pay = 1 的 transaction_id
     let last_transaction = sqlx::query!(
-        "SELECT transaction_id FROM pay_records WHERE id < ? AND is_pay = 1 ORDER BY id DESC LIMIT 1",
-        min_id
+        "SELECT transaction_id FROM pay_records WHERE id < ? AND is_pay = 1 AND address = ? ORDER BY id DESC LIMIT 1",
+        min_id,
+        address
     )
         .fetch_optional(&pool)
         .await?;
 
     let last_transaction_id = last_transaction.map(|record| record.transaction_id);
 
-    // 将未支付的记录存入一个可变的集合，方便后续匹配和移除
-    // 使用 BigDecimal 类型的金额
-    let mut pending_amounts: Vec<(i64, BigDecimal)> = pending_records
-        .iter()
-        .map(|record| {
-            (
-                record.id,
-                record.pay_token.clone(),
-            )
-        })
-        .collect();
+    // 按金额（归一化为毫厘整数）建立索引，避免每笔交易都线性扫描全部待支付记录
+    let mut pending_index: HashMap<i64, Vec<(i64, BigDecimal)>> = HashMap::new();
+    let mut remaining_ids: HashSet<i64> = HashSet::new();
+    for record in &pending_records {
+        pending_index
+            .entry(amount_key(&record.pay_token))
+            .or_insert_with(Vec::new)
+            .push((record.id, record.pay_token.clone()));
+        remaining_ids.insert(record.id);
+    }
 
     loop {
         let response = reqwest::get(&current_url).await?;
@@ -157,7 +808,8 @@ async fn fetch_and_process_transactions(
                 // 如果当前交易的 tx_id 等于上一个记录的 transaction_id，则停止遍历
                 if let Some(ref last_tx_id) = last_transaction_id {
                     if &transaction.tx_id == last_tx_id {
-                        println!("已达到已处理的最后一条交易记录，停止遍历。");
+                        println!("[{}] 已达到已处理的最后一条交易记录，停止遍历。", address);
+                        clear_sync_state(&pool, &address).await?;
                         return Ok(());
                     }
                 }
@@ -182,18 +834,19 @@ async fn fetch_and_process_transactions(
                     Some(v) => v.clone(),
                     None => continue, // 如果 value 为空，跳过此交易
                 };
-                // 检查 token_info 是否存在，并且 symbol 是否为 "USDT"。这个是只获取usdt的链接
+
+                // 按合约地址匹配配置中监控的代币，而不是信任对端返回的 symbol
                 let token_info = match transaction.token_info.as_ref() {
-                    Some(info) if info.symbol == "USDT" => info,
-                    _ => continue, // 如果 token_info 不存在或 symbol 不是 "USDT"，跳过此交易
+                    Some(info) => info,
+                    None => continue, // 如果 token_info 为空，跳过此交易
+                };
+                let token_cfg = match tokens.iter().find(|t| t.contract == token_info.address) {
+                    Some(cfg) => cfg,
+                    None => continue, // 不在本账户监控的代币列表中，跳过此交易
                 };
-                // let token_info = match transaction.token_info.as_ref() {
-                //     Some(info) => info,
-                //     None => continue, // 如果 token_info 为空，跳过此交易
-                // };
 
-                // 获取 decimals 值
-                let decimals = token_info.decimals;
+                // 获取 decimals 值，以配置为准
+                let decimals = token_cfg.decimals;
 
                 // 将 value_str 转换为 BigDecimal
                 let value_decimal = BigDecimal::from_str(&value_str).unwrap_or_else(|_| BigDecimal::from(0));
@@ -204,101 +857,70 @@ async fn fetch_and_process_transactions(
 
                 // 打印可读金额
                 println!(
-                    "[TRC20] 第{}页第{}笔交易：代币 {} 转账金额 {:.6}, 从: {}, 到: {}, 交易ID: {}",
+                    "[{}][TRC20] 第{}页第{}笔交易：代币 {} 转账金额 {:.6}, 从: {}, 到: {}, 交易ID: {}",
+                    address,
                     page,
                     i + 1,
-                    token_info.symbol,
+                    token_cfg.symbol,
                     readable_amount,
                     from,
                     to,
                     transaction.tx_id
                 );
-                for (id, amount) in &pending_amounts {
-                    println!("记录ID: {}, 金额: {}", id, amount);
-                    println!("当前交易金额: {}, 钱包地址: {}", readable_amount, from);
-                }
-                // 检查当前交易金额和发送者是否在未支付记录中
-                if let Some(pos) = pending_amounts.iter().position(|(_, amount)| {
-                    // 判断 readable_amount 是否在 amount - 2 的范围内
-                    let in_range = &readable_amount >= &(amount - BigDecimal::from(2)) && &readable_amount < amount;
-
-                    // 比较 amount 和 readable_amount 的前三位小数
-                    let amount_truncated = amount.with_scale(3); // 取 amount 的前三位小数
-                    let readable_truncated = readable_amount.with_scale(3); // 取 readable_amount 的前三位小数
-                    let decimals_match = amount_truncated == readable_truncated;
-
-                    // 满足范围和小数匹配条件
-                    in_range && decimals_match
-                }) {
-                    let (id, _) = pending_amounts.remove(pos);
-
-                    // 开启事务
-                    let mut tx = pool.begin().await?;
-
-                    // 获取 user_id 和当前的 gold_coins
-                    let (user_id, current_gold_coins): (i64, BigDecimal) = sqlx::query_as::<_, (i64, BigDecimal)>(
-                        "SELECT user.id as user_id, user.gold_coins as gold_coins FROM user
-     INNER JOIN pay_records ON user.id = pay_records.user_id
-     WHERE pay_records.id = ?"
-                    )
-                        .bind(id) // 绑定 `id` 参数
-                        .fetch_one(&mut *tx)
-                        .await?;
-                    println!("开始更新记录，交易ID: {}, 用户ID: {}, 金额: {}", transaction.tx_id, user_id, readable_amount);
-
-                    // 计算 pay_before_gold_coins 和 pay_after_gold_coins
-                    let pay_before_gold_coins = current_gold_coins.clone();
-                    let pay_after_gold_coins = &current_gold_coins + &readable_amount;
-
-                    // 更新 pay_records 表
-                    sqlx::query!(
-                        "UPDATE pay_records
-                         SET transaction_id = ?, pay_token = ?, is_pay = 1,
-                             pay_before_gold_coins = ?, pay_after_gold_coins = ?
-                         WHERE id = ?",
-                        transaction.tx_id,
-                        readable_amount.to_string(),
-                        pay_before_gold_coins.to_string(),
-                        pay_after_gold_coins.to_string(),
-                        id
-                    )
-                        .execute(&mut *tx)
-                        .await?;
-                    // 更新 user 表中的 gold_coins
-                    sqlx::query!(
-                        "UPDATE user SET gold_coins = ? WHERE id = ?",
-                        pay_after_gold_coins.to_string(),
-                        user_id
-                    )
-                        .execute(&mut *tx)
-                        .await?;
-
-                    // 提交事务
-                    tx.commit().await?;
-
-                    println!("成功更新记录，id: {}", id);
+                // 按金额索引做 O(1) 分桶查找，而不是线性扫描全部待支付记录
+                let bucket_key = amount_key(&readable_amount);
+                let matched_id = pending_index.get_mut(&bucket_key).and_then(|bucket| {
+                    println!("[{}] 命中金额桶 {}，桶内 {} 条待匹配记录", address, bucket_key, bucket.len());
+                    let pos = bucket.iter().position(|(_, amount)| {
+                        // 判断 readable_amount 是否在 amount - 2 的范围内
+                        let in_range = &readable_amount >= &(amount - BigDecimal::from(2)) && &readable_amount < amount;
+
+                        // 比较 amount 和 readable_amount 的前三位小数
+                        let amount_truncated = amount.with_scale(3); // 取 amount 的前三位小数
+                        let readable_truncated = readable_amount.with_scale(3); // 取 readable_amount 的前三位小数
+                        let decimals_match = amount_truncated == readable_truncated;
+
+                        // 满足范围和小数匹配条件
+                        in_range && decimals_match
+                    })?;
+                    let (id, _) = bucket.remove(pos);
+                    Some(id)
+                });
+
+                if let Some(id) = matched_id {
+                    // 同步从待支付 id 集合中移除，空集合即可提前结束本次轮询
+                    remaining_ids.remove(&id);
+
+                    mark_record_confirming(&pool, base_url, &address, &transaction.tx_id, &readable_amount, id).await?;
                 }
             }
 
-            // 如果 pending_amounts 已空，说明所有待处理记录已更新，可停止遍历
-            if pending_amounts.is_empty() {
-                println!("所有待处理记录已更新，停止遍历。");
+            // 如果 remaining_ids 已空，说明所有待处理记录已更新，可提前结束本次轮询
+            if remaining_ids.is_empty() {
+                println!("[{}] 所有待处理记录已更新，停止遍历。", address);
+                clear_sync_state(&pool, &address).await?;
                 return Ok(());
             }
 
             if let Some(next_page) = transaction_data.next.clone() {
-                current_url = format!("https://nile.trongrid.io{}", next_page);
+                // 持久化本页处理到的位置和下一页 token，崩溃重启后可以跳过已下载过的页面
+                if let Some(last_seen) = transaction_data.data.last() {
+                    save_sync_state(&pool, &address, &last_seen.tx_id, Some(&next_page)).await?;
+                }
+
+                current_url = format!("{}{}", base_url, next_page);
                 page += 1;
                 tokio::time::sleep(Duration::from_millis(500)).await;
             } else {
-                println!("没有更多页面，停止遍历。");
+                println!("[{}] 没有更多页面，停止遍历。", address);
+                clear_sync_state(&pool, &address).await?;
                 break;
             }
         } else {
-            println!("获取交易失败：HTTP 状态码 {}", response.status());
+            println!("[{}] 获取交易失败：HTTP 状态码 {}", address, response.status());
             break;
         }
     }
 
     Ok(())
-}
\ No newline at end of file
+}